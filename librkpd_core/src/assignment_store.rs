@@ -0,0 +1,143 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Durable storage for `key_pool`'s `key_id` → provisioned-entry assignments, so a caller that
+//! was handed a key before an rkpd restart is handed the same key afterward, not just for the
+//! lifetime of the process that first assigned it.
+//!
+//! Each `IRemotelyProvisionedComponent` gets its own file under [`STORE_DIR`], CBOR-encoded as an
+//! array of `[keyId, keyBlob, encodedCertChain, expiresAtUnixSecs]` records.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ciborium::value::Value;
+use log::{error, warn};
+
+/// Directory holding one assignment file per registered `IRemotelyProvisionedComponent`.
+const STORE_DIR: &str = "/data/misc/remoteprovisioning/rkpd_assignments";
+
+/// A single persisted assignment. Expiry is wall-clock time rather than `key_pool`'s `Instant`,
+/// since only wall-clock time means anything across a restart.
+pub struct PersistedAssignment {
+    pub key_id: i32,
+    pub key_blob: Vec<u8>,
+    pub encoded_cert_chain: Vec<u8>,
+    pub expires_at: SystemTime,
+}
+
+/// Loads the previously persisted assignments for `irpc_name`, or an empty list if none have
+/// been persisted yet, or if the store couldn't be read (logged and treated the same as empty,
+/// since persistence is an optimization for assignment stability, not a correctness requirement
+/// `getRemotelyProvisionedKey` depends on to produce a key at all).
+pub fn load(irpc_name: &str) -> Vec<PersistedAssignment> {
+    let path = path_for(irpc_name);
+    let bytes = match fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Vec::new(),
+        Err(e) => {
+            warn!("failed to read persisted assignments from {}: {}", path.display(), e);
+            return Vec::new();
+        }
+    };
+    match decode(&bytes) {
+        Ok(assignments) => assignments,
+        Err(e) => {
+            warn!("failed to decode persisted assignments from {}: {}", path.display(), e);
+            Vec::new()
+        }
+    }
+}
+
+/// Overwrites the persisted assignments for `irpc_name` with `assignments`. Failures are logged
+/// and otherwise swallowed rather than propagated; see [`load`] for why.
+pub fn save(irpc_name: &str, assignments: &[PersistedAssignment]) {
+    let path = path_for(irpc_name);
+    if let Err(e) = fs::create_dir_all(STORE_DIR) {
+        error!("failed to create {}: {}", STORE_DIR, e);
+        return;
+    }
+    // Write to a temporary file and rename it into place, so a crash mid-write can't leave a
+    // truncated, undecodable assignment file behind.
+    let tmp_path = path.with_extension("tmp");
+    let result = fs::write(&tmp_path, encode(assignments)).and_then(|_| fs::rename(&tmp_path, &path));
+    if let Err(e) = result {
+        error!("failed to persist assignments to {}: {}", path.display(), e);
+    }
+}
+
+fn path_for(irpc_name: &str) -> PathBuf {
+    // `irpc_name` is a binder service name (e.g.
+    // "android.hardware.security.keymint.IRemotelyProvisionedComponent/default"); turn it into a
+    // single safe path component.
+    let file_name: String =
+        irpc_name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    Path::new(STORE_DIR).join(file_name)
+}
+
+fn encode(assignments: &[PersistedAssignment]) -> Vec<u8> {
+    let entries = assignments
+        .iter()
+        .map(|assignment| {
+            let expires_at_secs = assignment
+                .expires_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or(Duration::ZERO)
+                .as_secs();
+            Value::Array(vec![
+                Value::Integer(assignment.key_id.into()),
+                Value::Bytes(assignment.key_blob.clone()),
+                Value::Bytes(assignment.encoded_cert_chain.clone()),
+                Value::Integer(expires_at_secs.into()),
+            ])
+        })
+        .collect();
+    let mut bytes = Vec::new();
+    ciborium::ser::into_writer(&Value::Array(entries), &mut bytes)
+        .expect("encoding a list of byte strings and integers cannot fail");
+    bytes
+}
+
+fn decode(bytes: &[u8]) -> Result<Vec<PersistedAssignment>, String> {
+    let value: Value = ciborium::de::from_reader(bytes).map_err(|e| e.to_string())?;
+    let Value::Array(entries) = value else {
+        return Err("top-level CBOR value was not an array".to_string());
+    };
+    entries.into_iter().map(decode_entry).collect()
+}
+
+fn decode_entry(value: Value) -> Result<PersistedAssignment, String> {
+    let Value::Array(fields) = value else {
+        return Err("assignment entry was not an array".to_string());
+    };
+    let [key_id, key_blob, encoded_cert_chain, expires_at_secs]: [Value; 4] = fields
+        .try_into()
+        .map_err(|_| "assignment entry did not have exactly 4 fields".to_string())?;
+    let key_id: i32 =
+        key_id.as_integer().and_then(|i| i.try_into().ok()).ok_or("bad keyId field")?;
+    let key_blob = key_blob.into_bytes().map_err(|_| "bad keyBlob field")?;
+    let encoded_cert_chain = encoded_cert_chain.into_bytes().map_err(|_| "bad encodedCertChain field")?;
+    let expires_at_secs: u64 = expires_at_secs
+        .as_integer()
+        .and_then(|i| i.try_into().ok())
+        .ok_or("bad expiresAt field")?;
+    Ok(PersistedAssignment {
+        key_id,
+        key_blob,
+        encoded_cert_chain,
+        expires_at: UNIX_EPOCH + Duration::from_secs(expires_at_secs),
+    })
+}
@@ -0,0 +1,179 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Asynchronous key retrieval for `IRegistration::getKeyAsync`/`cancelGetKey`.
+//!
+//! `getRemotelyProvisionedKey` answers from the key pool as it stands and fails with
+//! `OUT_OF_KEYS` if the pool is exhausted. `getKeyAsync` instead queues the request and returns
+//! immediately; a single worker thread per `MyRegistration` drains the queue, waiting for a pool
+//! top-up if needed, and reports the result to the caller-supplied callback.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+use android_security_rkpd::aidl::android::security::rkpd::{
+    ErrorCode::ErrorCode, IGetKeyCallback::IGetKeyCallback,
+    RemotelyProvisionedKey::RemotelyProvisionedKey,
+};
+use android_security_rkpd::binder::{IBinder, SpIBinder, Strong};
+use log::warn;
+
+use crate::error::{Error, Result};
+
+/// A `getKeyAsync` request waiting to be serviced.
+struct PendingRequest {
+    key_id: i32,
+    callback: Strong<dyn IGetKeyCallback>,
+}
+
+/// The request the worker thread is currently servicing, if any. `abort` is handed to
+/// `fetch_key` so [`AsyncKeyWorker::cancel`] and [`AsyncKeyWorker::shut_down`] can interrupt a
+/// fetch that's already in flight, not just ones still sitting in `pending`.
+struct InFlight {
+    callback: SpIBinder,
+    abort: Arc<AtomicBool>,
+}
+
+struct Queue {
+    pending: VecDeque<PendingRequest>,
+    in_flight: Option<InFlight>,
+    shutdown: bool,
+}
+
+/// Owns the queue of outstanding `getKeyAsync` requests for one `MyRegistration` instance and
+/// hands them to its worker thread.
+pub struct AsyncKeyWorker {
+    queue: Mutex<Queue>,
+    cv: Condvar,
+}
+
+impl AsyncKeyWorker {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            queue: Mutex::new(Queue { pending: VecDeque::new(), in_flight: None, shutdown: false }),
+            cv: Condvar::new(),
+        })
+    }
+
+    /// Queues `key_id` for asynchronous retrieval. If `callback` already has a request pending
+    /// (queued or already being serviced), the duplicate is dropped rather than queued again.
+    /// The caller isn't notified of the drop: `onSuccess`/`onError`/`onCancel` is still coming
+    /// for the original request on this same callback, and reporting the duplicate through
+    /// `onError` would misrepresent that the original request failed.
+    pub fn enqueue(&self, key_id: i32, callback: Strong<dyn IGetKeyCallback>) {
+        let mut queue = self.queue.lock().unwrap();
+        let target = callback.as_binder();
+        let already_pending = queue.pending.iter().any(|req| req.callback.as_binder() == target)
+            || queue.in_flight.as_ref().map_or(false, |in_flight| in_flight.callback == target);
+        if already_pending {
+            warn!("getKeyAsync called again for a callback with a request already pending (keyId {}); ignoring the duplicate", key_id);
+            return;
+        }
+        queue.pending.push_back(PendingRequest { key_id, callback });
+        self.cv.notify_one();
+    }
+
+    /// Withdraws `callback`'s outstanding request, notifying it via `onCancel`. Returns whether a
+    /// matching request was found, whether it was still queued or already being serviced by the
+    /// worker thread; in the latter case cancellation is best-effort, since the in-flight fetch
+    /// notices the abort signal only the next time it polls for it (see
+    /// [`crate::key_pool::KeyPool::assign_blocking`]) rather than immediately.
+    pub fn cancel(&self, callback: &Strong<dyn IGetKeyCallback>) -> bool {
+        let mut queue = self.queue.lock().unwrap();
+        let target = callback.as_binder();
+        if let Some(position) = queue.pending.iter().position(|req| req.callback.as_binder() == target)
+        {
+            let request = queue.pending.remove(position).unwrap();
+            drop(queue);
+            if let Err(e) = request.callback.onCancel() {
+                warn!("failed to deliver onCancel: {:?}", e);
+            }
+            return true;
+        }
+        if let Some(in_flight) = &queue.in_flight {
+            if in_flight.callback == target {
+                in_flight.abort.store(true, Ordering::Relaxed);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Requests that the worker thread stop after its current request (if any) completes. Also
+    /// signals the current request's abort flag, so a fetch blocked waiting on the backend (e.g.
+    /// [`crate::key_pool::KeyPool::assign_blocking`]) is interrupted instead of making `shut_down`
+    /// (and the `Drop` that calls it) wait out the fetch's full timeout.
+    pub fn shut_down(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.shutdown = true;
+        if let Some(in_flight) = &queue.in_flight {
+            in_flight.abort.store(true, Ordering::Relaxed);
+        }
+        self.cv.notify_all();
+    }
+}
+
+fn notify_error(callback: &Strong<dyn IGetKeyCallback>, error_code: ErrorCode, message: &str) {
+    if let Err(e) = callback.onError(error_code, message) {
+        warn!("failed to deliver onError: {:?}", e);
+    }
+}
+
+/// Spawns the worker thread that drains `worker`'s queue, servicing each request with
+/// `fetch_key` and reporting the outcome to its callback. `fetch_key` is handed an abort flag it
+/// should poll and bail out on (returning `Error::RequestCancelled`), so that
+/// [`AsyncKeyWorker::cancel`] and [`AsyncKeyWorker::shut_down`] can interrupt a fetch already in
+/// progress instead of only ones still queued.
+pub fn spawn(
+    worker: Arc<AsyncKeyWorker>,
+    fetch_key: impl Fn(i32, &AtomicBool) -> Result<RemotelyProvisionedKey> + Send + 'static,
+) -> JoinHandle<()> {
+    thread::Builder::new()
+        .name("rkpd-async-key".into())
+        .spawn(move || loop {
+            let (request, abort) = {
+                let mut queue = worker.queue.lock().unwrap();
+                loop {
+                    if queue.shutdown {
+                        return;
+                    }
+                    if let Some(request) = queue.pending.pop_front() {
+                        let abort = Arc::new(AtomicBool::new(false));
+                        queue.in_flight =
+                            Some(InFlight { callback: request.callback.as_binder(), abort: abort.clone() });
+                        break (request, abort);
+                    }
+                    queue = worker.cv.wait(queue).unwrap();
+                }
+            };
+            let result = fetch_key(request.key_id, &abort);
+            worker.queue.lock().unwrap().in_flight = None;
+            match result {
+                Ok(key) => {
+                    if let Err(e) = request.callback.onSuccess(&key) {
+                        warn!("failed to deliver onSuccess: {:?}", e);
+                    }
+                }
+                Err(Error::RequestCancelled) => {
+                    if let Err(e) = request.callback.onCancel() {
+                        warn!("failed to deliver onCancel: {:?}", e);
+                    }
+                }
+                Err(e) => notify_error(&request.callback, e.error_code(), &e.to_string()),
+            }
+        })
+        .expect("failed to spawn rkpd-async-key worker thread")
+}
@@ -0,0 +1,194 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Construction of a Boot Certificate Chain (BCC), the CBOR structure that backs
+//! `RemotelyProvisionedKey::encodedCertChain`.
+//!
+//! A BCC is a CBOR array whose first element is a COSE_Key for the root/device public key,
+//! followed by one DICE certificate per measured layer. Each certificate is a COSE_Sign1 CWT
+//! whose payload carries the issuer, subject, measured code/config/authority hashes, DICE mode,
+//! and the subject's own public key (per the Open Profile for DICE), signed by the parent
+//! layer's attestation key. CDI values are derived layer by layer using the open-dice main flow.
+
+use ciborium::value::Value;
+use coset::{iana, CborSerializable, CoseKey, CoseKeyBuilder, CoseSign1};
+use open_dice::{Cdi, DiceMode, InputValues};
+use ring::signature::{UnparsedPublicKey, ED25519};
+
+use android_security_rkpd::aidl::android::security::rkpd::ResponseCode::ResponseCode;
+
+use crate::error::{Error, Result};
+
+/// CWT claim label for the subject's public key, per the Open Profile for DICE.
+const SUBJECT_PUBLIC_KEY_LABEL: i128 = -4670552;
+
+/// One layer to measure into the chain, ordered from the layer closest to the root to the leaf.
+pub struct Layer {
+    /// Hash of the code running at this layer.
+    pub code_hash: [u8; 64],
+    /// CBOR-encoded configuration descriptor for this layer.
+    pub config_descriptor: Vec<u8>,
+    /// Hash identifying the authority that signed this layer's code.
+    pub authority_hash: [u8; 64],
+    /// Whether this layer is running in normal, debug, or recovery mode.
+    pub mode: DiceMode,
+}
+
+fn internal_error() -> Error {
+    Error::Rkp(ResponseCode::INTERNAL_ERROR)
+}
+
+/// Builds a BCC starting from `root_cdi_attest`/`root_cdi_seal` and measuring each of `layers`
+/// in order. Returns the CBOR-encoded chain (suitable for `encodedCertChain`) together with an
+/// opaque key blob that identifies the resulting leaf key (suitable for `keyBlob`).
+pub fn build_bcc(
+    root_cdi_attest: &Cdi,
+    root_cdi_seal: &Cdi,
+    layers: &[Layer],
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    // `main_flow` signs each certificate with the key derived from the parent's CDI_Attest via the
+    // private key seed, so the root COSE_Key must come from the same derivation.
+    let root_seed =
+        open_dice::derive_cdi_private_key_seed(root_cdi_attest).map_err(|_| internal_error())?;
+    let (root_public_key, _) =
+        open_dice::keypair_from_seed(root_seed.as_array()).map_err(|_| internal_error())?;
+
+    let mut entries = Vec::with_capacity(layers.len() + 1);
+    entries.push(
+        public_key_to_cose_key(&root_public_key).to_cbor_value().map_err(|_| internal_error())?,
+    );
+
+    let mut cdi_attest = *root_cdi_attest;
+    let mut cdi_seal = *root_cdi_seal;
+    for layer in layers {
+        let input_values = InputValues::new(
+            layer.code_hash,
+            open_dice::Config::Descriptor(layer.config_descriptor.clone()),
+            layer.authority_hash,
+            layer.mode,
+            [0u8; 64],
+        );
+        let (next_cdi_attest, next_cdi_seal, cert) =
+            open_dice::main_flow(&cdi_attest, &cdi_seal, &input_values)
+                .map_err(|_| internal_error())?;
+        let sign1 = CoseSign1::from_slice(&cert).map_err(|_| internal_error())?;
+        entries.push(sign1.to_cbor_value().map_err(|_| internal_error())?);
+        cdi_attest = next_cdi_attest;
+        cdi_seal = next_cdi_seal;
+    }
+
+    let mut encoded_chain = Vec::new();
+    ciborium::ser::into_writer(&Value::Array(entries), &mut encoded_chain)
+        .map_err(|_| internal_error())?;
+
+    let mut key_blob = Vec::with_capacity(cdi_attest.len() + cdi_seal.len());
+    key_blob.extend_from_slice(&cdi_attest);
+    key_blob.extend_from_slice(&cdi_seal);
+    Ok((encoded_chain, key_blob))
+}
+
+fn public_key_to_cose_key(public_key: &[u8]) -> CoseKey {
+    CoseKeyBuilder::new_okp_key()
+        .algorithm(iana::Algorithm::EdDSA)
+        .param(
+            iana::OkpKeyParameter::Crv as i64,
+            Value::from(iana::EllipticCurve::Ed25519 as u64),
+        )
+        .param(iana::OkpKeyParameter::X as i64, Value::Bytes(public_key.to_vec()))
+        .build()
+}
+
+/// Extracts the raw Ed25519 public key bytes (the COSE `X` parameter) from a COSE_Key.
+fn cose_key_public_key(key: &CoseKey) -> Result<Vec<u8>> {
+    key.params
+        .iter()
+        .find(|(label, _)| *label == coset::Label::Int(iana::OkpKeyParameter::X as i64))
+        .and_then(|(_, value)| value.as_bytes().cloned())
+        .ok_or_else(internal_error)
+}
+
+/// Parses a `CoseSign1` DICE certificate's payload and returns the embedded subject public key.
+fn subject_public_key(cert: &CoseSign1) -> Result<CoseKey> {
+    let payload = cert.payload.as_ref().ok_or_else(internal_error)?;
+    let claims: Value = ciborium::de::from_reader(payload.as_slice()).map_err(|_| internal_error())?;
+    let Value::Map(claims) = claims else {
+        return Err(internal_error());
+    };
+    let encoded_key = claims
+        .into_iter()
+        .find(|(label, _)| matches!(label, Value::Integer(i) if i128::from(*i) == SUBJECT_PUBLIC_KEY_LABEL))
+        .and_then(|(_, value)| value.into_bytes().ok())
+        .ok_or_else(internal_error)?;
+    CoseKey::from_slice(&encoded_key).map_err(|_| internal_error())
+}
+
+/// Parses a CBOR-encoded BCC back into its COSE_Key root and ordered `CoseSign1` certificates.
+/// Exposed for tests and for callers that need to walk an existing chain.
+pub fn parse_bcc(encoded_chain: &[u8]) -> Result<(CoseKey, Vec<CoseSign1>)> {
+    let value: Value =
+        ciborium::de::from_reader(encoded_chain).map_err(|_| internal_error())?;
+    let Value::Array(entries) = value else {
+        return Err(internal_error());
+    };
+    let mut entries = entries.into_iter();
+    let root = entries.next().ok_or_else(internal_error)?;
+    let root_key = CoseKey::from_cbor_value(root).map_err(|_| internal_error())?;
+    let certs = entries
+        .map(|entry| CoseSign1::from_cbor_value(entry).map_err(|_| internal_error()))
+        .collect::<Result<Vec<_>>>()?;
+    Ok((root_key, certs))
+}
+
+/// Verifies that every certificate in a parsed BCC is signed by the preceding layer's public
+/// key, chaining from `root_key`.
+pub fn verify_chain(root_key: &CoseKey, certs: &[CoseSign1]) -> Result<()> {
+    let mut signer_key = cose_key_public_key(root_key)?;
+    for cert in certs {
+        let public_key = UnparsedPublicKey::new(&ED25519, signer_key.as_slice());
+        cert.verify_signature(&[], |signature, message| {
+            public_key.verify(message, signature).map_err(|_| internal_error())
+        })?;
+        signer_key = cose_key_public_key(&subject_public_key(cert)?)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_layer(seed: u8) -> Layer {
+        Layer {
+            code_hash: [seed; 64],
+            config_descriptor: vec![seed; 4],
+            authority_hash: [seed.wrapping_add(1); 64],
+            mode: DiceMode::Normal,
+        }
+    }
+
+    #[test]
+    fn bcc_round_trips_and_chain_verifies() {
+        let root_cdi_attest: Cdi = [0x11; 32];
+        let root_cdi_seal: Cdi = [0x22; 32];
+        let layers = vec![test_layer(1), test_layer(2)];
+
+        let (encoded_chain, key_blob) =
+            build_bcc(&root_cdi_attest, &root_cdi_seal, &layers).expect("build_bcc failed");
+        assert_eq!(key_blob.len(), 64);
+
+        let (root_key, certs) = parse_bcc(&encoded_chain).expect("parse_bcc failed");
+        assert_eq!(certs.len(), layers.len());
+        verify_chain(&root_key, &certs).expect("DICE chain did not verify");
+    }
+}
@@ -0,0 +1,101 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Error handling for rkpd.
+
+use android_security_rkpd::aidl::android::security::rkpd::{
+    ErrorCode::ErrorCode, ResponseCode::ResponseCode,
+};
+use android_security_rkpd::binder::Status;
+use log::error;
+
+/// Convenience alias for the `Result` type used throughout the rkpd implementation, fixing the
+/// error type to [`Error`].
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Internal error type for rkpd. Every fallible operation in this crate should return one of
+/// these variants rather than panicking or returning a dummy success value, so that the binder
+/// boundary always has a real `ResponseCode` to report back to the caller.
+#[derive(Debug)]
+pub enum Error {
+    /// A failure carrying the `ResponseCode` that should be surfaced to the caller, e.g. an
+    /// exhausted key pool, an unavailable backend, or an internal error.
+    Rkp(ResponseCode),
+    /// Waiting for a remotely provisioned key to become available exceeded the allotted
+    /// deadline. This is tracked as its own variant so callers and logs can tell a slow backend
+    /// apart from a backend that actively refused the request, but it still carries the
+    /// `ResponseCode` (typically `OUT_OF_KEYS`) that pre-existing clients already know how to
+    /// handle, so the binder-visible behavior doesn't change.
+    Timeout(ResponseCode),
+    /// The caller withdrew its request (e.g. via `cancelGetKey`) before it completed.
+    RequestCancelled,
+}
+
+impl Error {
+    /// The `ResponseCode` that should be reported to callers for this error.
+    fn response_code(&self) -> ResponseCode {
+        match self {
+            Self::Rkp(rc) => *rc,
+            Self::Timeout(rc) => *rc,
+            Self::RequestCancelled => ResponseCode::INTERNAL_ERROR,
+        }
+    }
+
+    /// The `ErrorCode` that should be reported through `IGetKeyCallback::onError` for this
+    /// error. Kept separate from [`Self::response_code`] since the async callback channel uses
+    /// its own, coarser error vocabulary.
+    pub(crate) fn error_code(&self) -> ErrorCode {
+        match self {
+            Self::Rkp(ResponseCode::OUT_OF_KEYS) | Self::Timeout(_) => ErrorCode::NO_KEYS_AVAILABLE,
+            Self::RequestCancelled => ErrorCode::CANCELLED,
+            Self::Rkp(_) => ErrorCode::INTERNAL_ERROR,
+        }
+    }
+
+    /// Converts this error into the binder `Status` that should cross the IPC boundary.
+    fn into_status(self) -> Status {
+        Status::new_service_specific_error(
+            self.response_code() as i32,
+            Some(&std::ffi::CString::new(format!("{:?}", self)).unwrap()),
+        )
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Rkp(rc) => write!(f, "rkp error: {:?}", rc),
+            Self::Timeout(rc) => write!(f, "timed out waiting for a key, reporting {:?}", rc),
+            Self::RequestCancelled => write!(f, "request was cancelled by the caller"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Logs `result`'s error (if any) with its full context, then converts it into the
+/// `BinderResult` that an AIDL method implementation should return. On success, `handler` is
+/// applied to the value to produce the binder-visible return type.
+///
+/// This keeps the logging and the binder conversion in one place, rather than sprinkling
+/// `.map_err` calls (or, as before, `.unwrap()`s) across every AIDL method.
+pub fn map_or_log_err<T, U, F>(result: Result<T>, handler: F) -> android_security_rkpd::binder::Result<U>
+where
+    F: FnOnce(T) -> U,
+{
+    result.map(handler).map_err(|e| {
+        error!("{}", e);
+        e.into_status()
+    })
+}
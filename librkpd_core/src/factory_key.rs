@@ -0,0 +1,59 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The pre-RKP factory-provisioned attestation key, served when remote key provisioning is
+//! disabled or its backend is unreachable and the caller does not require RKP specifically.
+//!
+//! This is currently a stub: no device has a real factory key wired up to [`load_factory_key`]
+//! yet, so every call to [`factory_provisioned_key`] reports `RKP_UNAVAILABLE` rather than
+//! actually falling back to a working key. Callers relying on this fallback to keep working
+//! without RKP will see that error until a real factory key source is plumbed in.
+
+use android_security_rkpd::aidl::android::security::rkpd::{
+    RemotelyProvisionedKey::RemotelyProvisionedKey, ResponseCode::ResponseCode,
+};
+use log::{error, info};
+
+use crate::error::{Error, Result};
+
+/// Returns this device's factory-provisioned attestation key and chain. Unlike a remotely
+/// provisioned key, this key is fixed at the factory and never rotates.
+///
+/// This key is not something rkpd can fabricate: it comes from whatever already attested this
+/// device before RKP existed, and serving anything else (e.g. a dummy all-zero blob) would hand
+/// out unverifiable attestation material that downstream verifiers would wrongly accept. So
+/// until KeyMint exposes the real factory key blob/chain to `load_factory_key`, this reports
+/// `RKP_UNAVAILABLE` instead of fabricating one.
+pub fn factory_provisioned_key() -> Result<RemotelyProvisionedKey> {
+    load_factory_key().ok_or_else(|| {
+        error!("no factory-provisioned attestation key is available on this device");
+        Error::Rkp(ResponseCode::RKP_UNAVAILABLE)
+    })
+}
+
+/// Loads this device's factory-provisioned attestation key blob and chain, or `None` if this
+/// device doesn't have one (e.g. it shipped with RKP-only provisioning).
+///
+/// TODO: fetch the real blob/chain from KeyMint's pre-RKP attestation key storage. Until that's
+/// wired up, there is no factory key to serve.
+fn load_factory_key() -> Option<RemotelyProvisionedKey> {
+    None
+}
+
+/// Upgrades a previously issued factory key blob. Since the factory key is static, this just
+/// round-trips the blob unchanged.
+pub fn upgrade_factory_key(key_id: i32, old_key_blob: &[u8]) -> Result<Vec<u8>> {
+    info!("keyId provided: {}", key_id);
+    Ok(old_key_blob.to_vec())
+}
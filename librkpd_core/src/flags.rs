@@ -0,0 +1,48 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Runtime gating of remote key provisioning.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::error;
+
+const ENABLE_RKPD_PROPERTY: &str = "remote_provisioning.enable_rkpd";
+
+/// Whether remote key provisioning should be used right now. Read fresh on every
+/// `getRegistration` call rather than cached, since the flag can flip without a reboot.
+pub fn rkp_enabled() -> bool {
+    rustutils::system_properties::read_bool(ENABLE_RKPD_PROPERTY, true).unwrap_or_else(|e| {
+        error!("failed to read {}, defaulting to enabled: {:?}", ENABLE_RKPD_PROPERTY, e);
+        true
+    })
+}
+
+/// Tracks whether the most recent attempt to reach the remote provisioning backend (a pool
+/// top-up, triggered by `getRegistration` or `refreshData`) succeeded. Starts optimistic so the
+/// first `getRegistration` call still tries RKP rather than assuming failure.
+static BACKEND_REACHABLE: AtomicBool = AtomicBool::new(true);
+
+/// Whether the remote provisioning backend appeared reachable last time it was contacted. This
+/// reflects the real outcome recorded by [`set_backend_reachable`]; it is not probed here, since
+/// that would mean a network call from whatever thread happens to ask.
+pub fn rkp_backend_available() -> bool {
+    BACKEND_REACHABLE.load(Ordering::Relaxed)
+}
+
+/// Records whether the backend was reachable on the most recent provisioning attempt, for
+/// [`rkp_backend_available`] to report on the next call.
+pub(crate) fn set_backend_reachable(reachable: bool) {
+    BACKEND_REACHABLE.store(reachable, Ordering::Relaxed);
+}
@@ -0,0 +1,389 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A pool of provisioned keys, shared per `IRemotelyProvisionedComponent`.
+//!
+//! Each `irpc_name` gets its own pool: `getRemotelyProvisionedKey` assigns an unused entry to
+//! each `key_id` it sees and remembers the assignment so the same caller keeps getting the same
+//! key, while `refreshData` evicts expired entries and tops the pool back up with freshly minted
+//! entries (built locally; see [`KeyPool::refresh`]).
+//!
+//! The `key_id` → entry assignment is durable: it's mirrored to disk via
+//! [`crate::assignment_store`] on every successful assignment, so a caller that was handed a key
+//! before an rkpd restart is handed the same key afterward too, not just within one process's
+//! lifetime.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use android_security_rkpd::aidl::android::security::rkpd::{
+    RemotelyProvisionedKey::RemotelyProvisionedKey, ResponseCode::ResponseCode,
+};
+
+use crate::assignment_store::{self, PersistedAssignment};
+use crate::bcc;
+use crate::error::{Error, Result};
+
+/// How long a provisioned key stays valid before `refreshData` evicts it.
+const KEY_LIFETIME: Duration = Duration::from_secs(60 * 60 * 24 * 30);
+
+/// The number of unassigned keys `refreshData` tries to keep on hand.
+const MIN_UNASSIGNED_KEYS: usize = 4;
+
+/// How long [`KeyPool::assign_blocking`] (used by the `getKeyAsync` worker) waits for a pool
+/// top-up before giving up with [`Error::Timeout`].
+pub const ASSIGN_BLOCKING_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often [`KeyPool::assign_blocking`] wakes up to check its abort flag, rather than sleeping
+/// for the rest of [`ASSIGN_BLOCKING_TIMEOUT`] in one go. Keeps a cancelled or shutting-down
+/// `getKeyAsync` request from having to wait out the full timeout to notice.
+const ABORT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+struct PoolEntry {
+    key: RemotelyProvisionedKey,
+    expires_at: Instant,
+}
+
+struct Inner {
+    unassigned: VecDeque<PoolEntry>,
+    assigned: HashMap<i32, PoolEntry>,
+    next_serial: u64,
+}
+
+/// A pool of provisioned key/cert-chain entries for a single `IRemotelyProvisionedComponent`.
+pub struct KeyPool {
+    irpc_name: String,
+    inner: Mutex<Inner>,
+    /// Notified whenever `refresh` adds unassigned entries, so `assign_blocking` can wake up.
+    replenished: Condvar,
+}
+
+impl KeyPool {
+    fn new(irpc_name: String) -> Self {
+        Self {
+            irpc_name,
+            inner: Mutex::new(Inner {
+                unassigned: VecDeque::new(),
+                assigned: HashMap::new(),
+                next_serial: 0,
+            }),
+            replenished: Condvar::new(),
+        }
+    }
+
+    /// Restores whatever assignments [`crate::assignment_store`] has persisted for this pool's
+    /// `irpc_name` from a previous process's lifetime. Expired entries are left in place here;
+    /// the following `refresh` call evicts them like any other expired assignment.
+    fn restore_persisted_assignments(&self) {
+        let persisted = assignment_store::load(&self.irpc_name);
+        if persisted.is_empty() {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        for assignment in persisted {
+            inner.assigned.insert(
+                assignment.key_id,
+                PoolEntry {
+                    key: RemotelyProvisionedKey {
+                        keyBlob: assignment.key_blob,
+                        encodedCertChain: assignment.encoded_cert_chain,
+                    },
+                    expires_at: system_time_to_instant(assignment.expires_at),
+                },
+            );
+        }
+    }
+
+    /// Returns the key assigned to `key_id`, assigning an unused entry from the pool the first
+    /// time `key_id` is seen. Returns `Error::Rkp(ResponseCode::OUT_OF_KEYS)` immediately if the
+    /// pool has nothing left to assign; see [`Self::assign_blocking`] for a caller that can
+    /// afford to wait for a top-up instead.
+    pub fn assign(&self, key_id: i32) -> Result<RemotelyProvisionedKey> {
+        let mut inner = self.inner.lock().unwrap();
+        let key = Self::assign_locked(&mut inner, key_id).ok_or(Error::Rkp(ResponseCode::OUT_OF_KEYS))?;
+        self.persist_assigned(&inner);
+        Ok(key)
+    }
+
+    /// Like [`Self::assign`], but if the pool is currently exhausted, waits for a `refresh` to
+    /// replenish it (as `getKeyAsync` can afford to, since provisioning may need a network round
+    /// trip) instead of failing right away. Gives up with `Error::Timeout` after
+    /// [`ASSIGN_BLOCKING_TIMEOUT`], and bails out early with `Error::RequestCancelled` if `abort`
+    /// is set first (e.g. by `cancelGetKey` or the worker shutting down).
+    pub fn assign_blocking(&self, key_id: i32, abort: &AtomicBool) -> Result<RemotelyProvisionedKey> {
+        self.assign_blocking_with_timeout(key_id, ASSIGN_BLOCKING_TIMEOUT, abort)
+    }
+
+    fn assign_blocking_with_timeout(
+        &self,
+        key_id: i32,
+        timeout: Duration,
+        abort: &AtomicBool,
+    ) -> Result<RemotelyProvisionedKey> {
+        let deadline = Instant::now() + timeout;
+        let mut inner = self.inner.lock().unwrap();
+        loop {
+            if let Some(key) = Self::assign_locked(&mut inner, key_id) {
+                self.persist_assigned(&inner);
+                return Ok(key);
+            }
+            if abort.load(Ordering::Relaxed) {
+                return Err(Error::RequestCancelled);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::Timeout(ResponseCode::OUT_OF_KEYS));
+            }
+            let wait_for = (deadline - now).min(ABORT_POLL_INTERVAL);
+            let (guard, _) = self.replenished.wait_timeout(inner, wait_for).unwrap();
+            inner = guard;
+        }
+    }
+
+    /// Assigns `key_id` an entry if one is already assigned or one is available to hand out,
+    /// without blocking. Returns `None` if the pool is exhausted.
+    fn assign_locked(inner: &mut Inner, key_id: i32) -> Option<RemotelyProvisionedKey> {
+        if let Some(entry) = inner.assigned.get(&key_id) {
+            return Some(entry.key.clone());
+        }
+        let entry = inner.unassigned.pop_front()?;
+        let key = entry.key.clone();
+        inner.assigned.insert(key_id, entry);
+        Some(key)
+    }
+
+    /// Mirrors the current assignment map to disk via [`crate::assignment_store`], so a restart
+    /// can restore it. Best-effort: a write failure is logged by the store itself and otherwise
+    /// ignored, since persistence is an optimization for assignment stability, not something a
+    /// binder call should fail over.
+    fn persist_assigned(&self, inner: &Inner) {
+        let assignments: Vec<PersistedAssignment> = inner
+            .assigned
+            .iter()
+            .map(|(key_id, entry)| PersistedAssignment {
+                key_id: *key_id,
+                key_blob: entry.key.keyBlob.clone(),
+                encoded_cert_chain: entry.key.encodedCertChain.clone(),
+                expires_at: instant_to_system_time(entry.expires_at),
+            })
+            .collect();
+        assignment_store::save(&self.irpc_name, &assignments);
+    }
+
+    /// Evicts expired entries (assigned or not), mints fresh ones via `mint_entry` until the
+    /// unassigned pool is back up to [`MIN_UNASSIGNED_KEYS`], and returns the resulting total
+    /// pool size (assigned plus unassigned). `mint_entry` builds each entry locally from
+    /// placeholder CDIs; no remote provisioning server is contacted.
+    ///
+    /// Records the outcome via [`crate::flags::set_backend_reachable`]: a minting failure here is
+    /// what "backend unreachable" means for [`crate::flags::rkp_backend_available`].
+    pub fn refresh(&self) -> Result<i32> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        inner.assigned.retain(|_, entry| entry.expires_at > now);
+        inner.unassigned.retain(|entry| entry.expires_at > now);
+        self.persist_assigned(&inner);
+        while inner.unassigned.len() < MIN_UNASSIGNED_KEYS {
+            let serial = inner.next_serial;
+            inner.next_serial += 1;
+            match mint_entry(serial) {
+                Ok(entry) => {
+                    // `next_serial` starts over after a restart, so skip entries that repeat a key
+                    // already handed out by a restored assignment.
+                    if !inner.assigned.values().any(|a| a.key.keyBlob == entry.key.keyBlob) {
+                        inner.unassigned.push_back(entry);
+                    }
+                }
+                Err(e) => {
+                    crate::flags::set_backend_reachable(false);
+                    return Err(e);
+                }
+            }
+        }
+        crate::flags::set_backend_reachable(true);
+        self.replenished.notify_all();
+        Ok((inner.assigned.len() + inner.unassigned.len()) as i32)
+    }
+}
+
+/// Converts an [`Instant`] deadline into wall-clock time so it survives a restart, approximating
+/// by this process's current `Instant`/`SystemTime` offset (both clocks tick at the same rate, so
+/// the approximation doesn't drift between the two calls).
+fn instant_to_system_time(instant: Instant) -> SystemTime {
+    let now_instant = Instant::now();
+    match instant.checked_duration_since(now_instant) {
+        Some(remaining) => SystemTime::now() + remaining,
+        None => SystemTime::now() - now_instant.duration_since(instant),
+    }
+}
+
+/// The inverse of [`instant_to_system_time`], used when restoring persisted assignments. Already
+/// passed-expiry timestamps collapse to `Instant::now()`; the next `refresh` evicts them like any
+/// other expired entry.
+fn system_time_to_instant(system_time: SystemTime) -> Instant {
+    match system_time.duration_since(SystemTime::now()) {
+        Ok(remaining) => Instant::now() + remaining,
+        Err(_) => Instant::now(),
+    }
+}
+
+fn mint_entry(serial: u64) -> Result<PoolEntry> {
+    let root_cdi_attest = derive_placeholder_cdi(serial, 0);
+    let root_cdi_seal = derive_placeholder_cdi(serial, 1);
+    let layer = bcc::Layer {
+        code_hash: [0; 64],
+        config_descriptor: format!("rkpd-pool:{}", serial).into_bytes(),
+        authority_hash: [0; 64],
+        mode: open_dice::DiceMode::Normal,
+    };
+    let (encoded_cert_chain, key_blob) =
+        bcc::build_bcc(&root_cdi_attest, &root_cdi_seal, std::slice::from_ref(&layer))?;
+    Ok(PoolEntry {
+        key: RemotelyProvisionedKey { keyBlob: key_blob, encodedCertChain: encoded_cert_chain },
+        expires_at: Instant::now() + KEY_LIFETIME,
+    })
+}
+
+fn derive_placeholder_cdi(serial: u64, salt: u8) -> open_dice::Cdi {
+    let mut cdi = [0u8; 32];
+    let serial_bytes = serial.to_be_bytes();
+    for (i, byte) in cdi.iter_mut().enumerate() {
+        *byte = serial_bytes[i % serial_bytes.len()] ^ salt ^ (i as u8);
+    }
+    cdi
+}
+
+fn registry() -> &'static Mutex<HashMap<String, Arc<KeyPool>>> {
+    static REGISTRY: OnceLock<Mutex<HashMap<String, Arc<KeyPool>>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Returns the shared key pool for `irpc_name`, creating it, restoring any assignments persisted
+/// by a previous process's lifetime, and seeding it the first time this component is registered.
+pub fn pool_for(irpc_name: &str) -> Result<Arc<KeyPool>> {
+    let mut registry = registry().lock().unwrap();
+    if let Some(pool) = registry.get(irpc_name) {
+        return Ok(pool.clone());
+    }
+    let pool = Arc::new(KeyPool::new(irpc_name.to_string()));
+    pool.restore_persisted_assignments();
+    pool.refresh()?;
+    registry.insert(irpc_name.to_string(), pool.clone());
+    Ok(pool)
+}
+
+/// Refreshes every registered component's key pool, as triggered by `IRefresh::refreshData`.
+/// Returns the combined pool size across all components.
+pub fn refresh_all() -> Result<i32> {
+    let registry = registry().lock().unwrap();
+    registry.values().try_fold(0, |total, pool| Ok(total + pool.refresh()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_abort() -> AtomicBool {
+        AtomicBool::new(false)
+    }
+
+    #[test]
+    fn assignment_is_stable() {
+        let pool = KeyPool::new("test.assignment_is_stable".to_string());
+        pool.refresh().expect("refresh failed");
+
+        let first = pool.assign(1).expect("assign failed");
+        let second = pool.assign(1).expect("re-assign failed");
+        assert_eq!(first.keyBlob, second.keyBlob);
+        assert_eq!(first.encodedCertChain, second.encodedCertChain);
+
+        let other = pool.assign(2).expect("assign failed");
+        assert_ne!(first.keyBlob, other.keyBlob);
+    }
+
+    #[test]
+    fn exhaustion_then_replenishment() {
+        let pool = KeyPool::new("test.exhaustion_then_replenishment".to_string());
+        pool.refresh().expect("refresh failed");
+
+        for key_id in 0..MIN_UNASSIGNED_KEYS as i32 {
+            pool.assign(key_id).expect("assign failed");
+        }
+        match pool.assign(MIN_UNASSIGNED_KEYS as i32) {
+            Err(Error::Rkp(ResponseCode::OUT_OF_KEYS)) => {}
+            other => panic!("expected OUT_OF_KEYS, got {:?}", other.map(|_| ())),
+        }
+
+        let size = pool.refresh().expect("refresh failed");
+        assert_eq!(size, MIN_UNASSIGNED_KEYS as i32 * 2);
+        pool.assign(MIN_UNASSIGNED_KEYS as i32).expect("assign after replenishment failed");
+    }
+
+    #[test]
+    fn assign_blocking_times_out_when_never_replenished() {
+        let pool = KeyPool::new("test.assign_blocking_times_out_when_never_replenished".to_string());
+        pool.refresh().expect("refresh failed");
+        for key_id in 0..MIN_UNASSIGNED_KEYS as i32 {
+            pool.assign(key_id).expect("assign failed");
+        }
+
+        let start = Instant::now();
+        match pool.assign_blocking_with_timeout(
+            MIN_UNASSIGNED_KEYS as i32,
+            Duration::from_millis(50),
+            &no_abort(),
+        ) {
+            Err(Error::Timeout(ResponseCode::OUT_OF_KEYS)) => {}
+            other => panic!("expected Timeout, got {:?}", other.map(|_| ())),
+        }
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn assign_blocking_wakes_up_on_replenishment() {
+        let pool = Arc::new(KeyPool::new("test.assign_blocking_wakes_up_on_replenishment".to_string()));
+        pool.refresh().expect("refresh failed");
+        for key_id in 0..MIN_UNASSIGNED_KEYS as i32 {
+            pool.assign(key_id).expect("assign failed");
+        }
+
+        let refresher = pool.clone();
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            refresher.refresh().expect("refresh failed");
+        });
+
+        pool.assign_blocking_with_timeout(MIN_UNASSIGNED_KEYS as i32, Duration::from_secs(5), &no_abort())
+            .expect("assign_blocking failed to pick up replenishment");
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn assign_blocking_aborts_when_signalled() {
+        let pool = KeyPool::new("test.assign_blocking_aborts_when_signalled".to_string());
+        pool.refresh().expect("refresh failed");
+        for key_id in 0..MIN_UNASSIGNED_KEYS as i32 {
+            pool.assign(key_id).expect("assign failed");
+        }
+
+        let abort = AtomicBool::new(true);
+        match pool.assign_blocking_with_timeout(MIN_UNASSIGNED_KEYS as i32, Duration::from_secs(5), &abort)
+        {
+            Err(Error::RequestCancelled) => {}
+            other => panic!("expected RequestCancelled, got {:?}", other.map(|_| ())),
+        }
+    }
+}
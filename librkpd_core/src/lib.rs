@@ -0,0 +1,36 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `librkpd_core` implements the registration, key-retrieval, and refresh logic behind rkpd,
+//! decoupled from the binder registration glue. The `rkpd` binary links this crate to back its
+//! `IRegistration`/`IRegistrar`/`IRefresh` binder services; other subsystems that want the same
+//! provisioning behavior without going through the `rkpd.registrar` binder hop (e.g. a
+//! keystore2-style in-process caller, or virtualization/pVM remote attestation) can link it
+//! directly instead.
+//!
+//! This crate depends on `coset`, `ciborium`, `ring`, `open_dice`, and `rustutils` in addition to
+//! what `rkpd`'s original single-crate layout already pulled in. Neither this crate's own Soong
+//! `rust_library` nor the `rkpd` `rust_defaults`/`rust_binary` updates that would add those deps
+//! and link against it are present in this tree, which carries no Android.bp (or Cargo.toml) for
+//! any crate to begin with; that build wiring needs to land alongside this source for `rkpd` and
+//! `librkpd_core`'s tests to build or run anywhere.
+
+pub mod assignment_store;
+pub mod async_key;
+pub mod bcc;
+pub mod error;
+pub mod factory_key;
+pub mod flags;
+pub mod key_pool;
+pub mod registration;
@@ -0,0 +1,170 @@
+// Copyright 2022, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Registration, key-retrieval, and refresh logic, independent of the binder `IRegistration`/
+//! `IRegistrar`/`IRefresh` traits. This is what `rkpd`'s binder services delegate to, and what
+//! other in-process callers (e.g. AVF pVM remote attestation) can link directly instead of going
+//! through the `rkpd.registrar` binder hop.
+
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use android_security_rkpd::aidl::android::security::rkpd::{
+    IGetKeyCallback::IGetKeyCallback, RemotelyProvisionedKey::RemotelyProvisionedKey,
+    ResponseCode::ResponseCode,
+};
+use android_security_rkpd::binder::Strong;
+use log::{error, info, warn};
+
+use crate::async_key::{self, AsyncKeyWorker};
+use crate::error::{Error, Result};
+use crate::factory_key;
+use crate::flags;
+use crate::key_pool::{self, KeyPool};
+
+enum Backend {
+    Rkp { pool: Arc<KeyPool>, async_worker: Arc<AsyncKeyWorker>, async_worker_thread: Option<JoinHandle<()>> },
+    FactoryKey,
+}
+
+/// Retrieves, upgrades, and asynchronously delivers keys for a single
+/// `IRemotelyProvisionedComponent` registration, backed either by remote key provisioning or, as
+/// a fallback, the device's factory-provisioned key (see [`crate::factory_key`] for the current
+/// state of that fallback). Obtain one via [`get_registration`].
+pub struct Registration(Backend);
+
+impl Registration {
+    fn rkp(pool: Arc<KeyPool>) -> Self {
+        let worker_pool = pool.clone();
+        let async_worker = AsyncKeyWorker::new();
+        let async_worker_thread = async_key::spawn(async_worker.clone(), move |key_id, abort| {
+            worker_pool.assign_blocking(key_id, abort)
+        });
+        Self(Backend::Rkp { pool, async_worker, async_worker_thread: Some(async_worker_thread) })
+    }
+
+    fn factory_key() -> Self {
+        Self(Backend::FactoryKey)
+    }
+
+    pub fn get_remotely_provisioned_key(&self, key_id: i32) -> Result<RemotelyProvisionedKey> {
+        match &self.0 {
+            Backend::Rkp { pool, .. } => pool.assign(key_id),
+            Backend::FactoryKey => factory_key::factory_provisioned_key(),
+        }
+    }
+
+    pub fn upgrade_key(&self, key_id: i32, old_key_blob: &[u8]) -> Result<Vec<u8>> {
+        match &self.0 {
+            Backend::Rkp { .. } => {
+                // A real upgrade means calling back into whichever IRemotelyProvisionedComponent
+                // HAL minted `old_key_blob`, which this `Registration` doesn't hold a reference
+                // to. Returning the blob unchanged would silently hide a KeyMint upgrade the
+                // caller actually needs, so report failure instead of pretending to have done it.
+                error!(
+                    "keyId {}: RKP key upgrade ({} byte blob) is not yet implemented",
+                    key_id,
+                    old_key_blob.len()
+                );
+                Err(Error::Rkp(ResponseCode::INTERNAL_ERROR))
+            }
+            Backend::FactoryKey => factory_key::upgrade_factory_key(key_id, old_key_blob),
+        }
+    }
+
+    pub fn get_key_async(&self, key_id: i32, callback: Strong<dyn IGetKeyCallback>) -> Result<()> {
+        match &self.0 {
+            Backend::Rkp { async_worker, .. } => {
+                async_worker.enqueue(key_id, callback);
+                Ok(())
+            }
+            Backend::FactoryKey => {
+                // Serving the factory key never needs a backend round trip, so resolve it
+                // synchronously instead of routing it through the async worker.
+                match factory_key::factory_provisioned_key() {
+                    Ok(key) => {
+                        let _ = callback.onSuccess(&key);
+                    }
+                    Err(e) => {
+                        let _ = callback.onError(e.error_code(), &e.to_string());
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub fn cancel_get_key(&self, callback: &Strong<dyn IGetKeyCallback>) -> Result<()> {
+        match &self.0 {
+            Backend::Rkp { async_worker, .. } => {
+                async_worker.cancel(callback);
+                Ok(())
+            }
+            Backend::FactoryKey => Ok(()),
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Backend::Rkp { async_worker, async_worker_thread, .. } = &mut self.0 {
+            async_worker.shut_down();
+            if let Some(thread) = async_worker_thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+/// The logic behind `IRegistrar::getRegistration`. Serves a remote-provisioning-backed
+/// [`Registration`] drawing from `irpc_name`'s shared key pool when RKP is enabled and its
+/// backend is reachable. Otherwise, unless `is_rkp_only` demands RKP specifically, falls back to
+/// the device's factory-provisioned key instead — or, until [`crate::factory_key`] is wired up to
+/// a real key source, to `RKP_UNAVAILABLE`, since there isn't yet a real factory key to serve.
+pub fn get_registration(irpc_name: &str, is_rkp_only: bool) -> Result<Registration> {
+    info!(
+        "Called rkpd to get registration for {} with isRkpOnly as {}",
+        irpc_name, is_rkp_only
+    );
+    if !flags::rkp_enabled() {
+        return fall_back_from_rkp(irpc_name, is_rkp_only, "RKP is disabled");
+    }
+    if !flags::rkp_backend_available() {
+        return fall_back_from_rkp(irpc_name, is_rkp_only, "RKP backend was last seen unreachable");
+    }
+    // The backend looked reachable last time it was contacted, but that can have changed since.
+    // The first time `irpc_name` is registered, `pool_for` makes the real attempt and updates
+    // `flags::rkp_backend_available` with the outcome, so a failure here still means falling back
+    // within this same call. An already-registered pool is returned as is, without an attempt.
+    match key_pool::pool_for(irpc_name) {
+        Ok(pool) => Ok(Registration::rkp(pool)),
+        Err(e) if is_rkp_only => Err(e),
+        Err(_) => fall_back_from_rkp(irpc_name, is_rkp_only, "RKP backend is unreachable"),
+    }
+}
+
+fn fall_back_from_rkp(irpc_name: &str, is_rkp_only: bool, reason: &str) -> Result<Registration> {
+    if is_rkp_only {
+        error!("{} but {} requires RKP-only operation", reason, irpc_name);
+        return Err(Error::Rkp(ResponseCode::RKP_UNAVAILABLE));
+    }
+    warn!("{}; falling back to the factory-provisioned key for {}", reason, irpc_name);
+    Ok(Registration::factory_key())
+}
+
+/// The logic behind `IRefresh::refreshData`: refreshes every component's key pool and returns
+/// the combined resulting pool size.
+pub fn refresh_data() -> Result<i32> {
+    key_pool::refresh_all()
+}
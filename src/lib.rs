@@ -12,11 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-//! This crate implements rkpd
-
-use log::info;
+//! This crate implements the rkpd binder services. All registration, key-retrieval, and refresh
+//! logic lives in `librkpd_core`; this crate is just the binder glue over it.
 
 use android_security_rkpd::aidl::android::security::rkpd::{
+    IGetKeyCallback::IGetKeyCallback,
     IRefresh::IRefresh,
     IRegistrar::IRegistrar,
     IRegistration::{BnRegistration, IRegistration},
@@ -25,20 +25,33 @@ use android_security_rkpd::aidl::android::security::rkpd::{
 
 use android_security_rkpd::binder::{BinderFeatures, Interface, Result as BinderResult, Strong};
 
-/// Implements IRegistration AIDL
-pub struct MyRegistration;
+use librkpd_core::error::map_or_log_err;
+use librkpd_core::registration::{self, Registration};
+
+/// Implements IRegistration AIDL by delegating to `librkpd_core::registration::Registration`.
+pub struct MyRegistration(Registration);
 
 impl Interface for MyRegistration {}
 
 impl IRegistration for MyRegistration {
     fn getRemotelyProvisionedKey(&self, key_id: i32) -> BinderResult<RemotelyProvisionedKey> {
-        info!("keyId provided: {}", key_id);
-        Ok(RemotelyProvisionedKey { keyBlob: vec![0; 32], encodedCertChain: vec![0; 32] })
+        map_or_log_err(self.0.get_remotely_provisioned_key(key_id), |key| key)
+    }
+
+    fn upgradeKey(&self, key_id: i32, old_key_blob: &[u8]) -> BinderResult<std::vec::Vec<u8>> {
+        map_or_log_err(self.0.upgrade_key(key_id, old_key_blob), |blob| blob)
+    }
+
+    fn getKeyAsync(
+        &self,
+        key_id: i32,
+        callback: &Strong<dyn IGetKeyCallback>,
+    ) -> BinderResult<()> {
+        map_or_log_err(self.0.get_key_async(key_id, callback.clone()), |_| ())
     }
 
-    fn upgradeKey(&self, key_id: i32, _old_key_blob: &[u8]) -> BinderResult<std::vec::Vec<u8>> {
-        info!("keyId provided: {}", key_id);
-        Ok(vec![0; 32])
+    fn cancelGetKey(&self, callback: &Strong<dyn IGetKeyCallback>) -> BinderResult<()> {
+        map_or_log_err(self.0.cancel_get_key(callback), |_| ())
     }
 }
 
@@ -54,15 +67,12 @@ impl IRegistrar for MyRegistrar {
         irpc_name: &str,
         is_rkp_only: bool,
     ) -> BinderResult<Strong<dyn IRegistration>> {
-        info!(
-            "Called rkpd to get registration for {} with isRkpOnly as {}",
-            irpc_name, is_rkp_only
-        );
-        let result = BnRegistration::new_binder(
-            MyRegistration {},
-            BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
-        );
-        Ok(result)
+        map_or_log_err(registration::get_registration(irpc_name, is_rkp_only), |registration| {
+            BnRegistration::new_binder(
+                MyRegistration(registration),
+                BinderFeatures { set_requesting_sid: true, ..BinderFeatures::default() },
+            )
+        })
     }
 }
 
@@ -73,6 +83,6 @@ impl Interface for MyRefresh {}
 
 impl IRefresh for MyRefresh {
     fn refreshData(&self) -> BinderResult<i32> {
-        Ok(0)
+        map_or_log_err(registration::refresh_data(), |pool_size| pool_size)
     }
 }